@@ -7,6 +7,12 @@ use era::{DecryptReader, EncryptWriter, EraArchive, EraWriter, TeaKeys};
 use sevenzip_plugin::prelude::*;
 use std::io::{Cursor, Write};
 
+/// A parsed archive over an owned, seekable source. Boxing the reader lets a
+/// random-access open keep operating on the caller's live stream (a file, a
+/// memory map) instead of a full in-memory copy, while the eager open simply
+/// boxes a `Cursor` over the bytes it already read.
+type OpenArchive = EraArchive<DecryptReader<Box<dyn ReadSeek>>>;
+
 /// ERA archive format handler.
 ///
 /// ERA is the archive format used by Ensemble Studios games.
@@ -14,13 +20,102 @@ use std::io::{Cursor, Write};
 #[derive(Default)]
 pub struct EraFormat {
     /// Parsed archive (for extraction)
-    archive: Option<EraArchive<DecryptReader<Cursor<Vec<u8>>>>>,
-    /// Raw archive data (needed for editing operations)
-    archive_data: Option<Vec<u8>>,
+    archive: Option<OpenArchive>,
     /// Items in the archive (maps to ERA entries, skipping entry 0)
     items: Vec<EraItem>,
     /// Physical size of the archive
     archive_size: u64,
+    /// When set, [`ArchiveReader::extract`] skips the Tiger-128 integrity
+    /// check. Default (`false`) is strict: a mismatch is an error.
+    skip_verify: bool,
+    /// Extra caller-supplied keysets to try before the built-in table during
+    /// [`ArchiveReader::open`]. Empty by default.
+    extra_keysets: Vec<TeaKeys>,
+    /// The keyset that successfully decrypted this archive, reused for
+    /// re-encryption in [`ArchiveUpdater::update_streaming`].
+    detected_keys: Option<TeaKeys>,
+    /// Code page used to decode filenames that are not valid UTF-8. Defaults
+    /// to CP437.
+    fallback_encoding: CodePage,
+}
+
+/// Built-in table of known ERA keysets, tried in order during auto-detection.
+///
+/// Different Ensemble Studios titles ship different TEA key schedules (TEA
+/// takes a 128-bit key as four `u32` words and runs 32 rounds over 64-bit
+/// blocks using the `0x9E3779B9` magic constant; only the schedule varies per
+/// game).
+///
+/// At present only the common default schedule has been recovered, so this
+/// table has a single entry — there is nothing cross-title to auto-detect yet.
+/// As other titles' schedules are reverse-engineered they are appended here;
+/// until then, callers with a non-default archive must supply its keyset via
+/// [`EraFormat::register_keys`].
+fn builtin_keysets() -> Vec<TeaKeys> {
+    vec![TeaKeys::default_archive_keys()]
+}
+
+/// Extraction errors that `sevenzip_plugin::Error` cannot represent as a typed
+/// variant. Returned by [`EraFormat::try_extract`] so callers can match on a
+/// checksum failure instead of parsing a message string.
+#[derive(Debug)]
+pub enum EraError {
+    /// The decompressed entry's Tiger-128 digest did not match the stored one.
+    ChecksumMismatch {
+        /// Item index (matches [`ArchiveReader::get_item`]).
+        index: usize,
+        /// The `tiger128` digest stored in the archive.
+        expected: [u8; 16],
+        /// The digest actually computed over the decompressed bytes.
+        actual: [u8; 16],
+    },
+    /// Any other failure, forwarded from the plugin layer.
+    Plugin(Error),
+}
+
+impl std::fmt::Display for EraError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EraError::ChecksumMismatch {
+                index,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "checksum mismatch for entry {}: expected {:02x?}, got {:02x?}",
+                index, expected, actual
+            ),
+            EraError::Plugin(e) => write!(f, "{:?}", e),
+        }
+    }
+}
+
+impl std::error::Error for EraError {}
+
+impl From<Error> for EraError {
+    fn from(e: Error) -> Self {
+        EraError::Plugin(e)
+    }
+}
+
+impl From<EraError> for Error {
+    fn from(e: EraError) -> Self {
+        match e {
+            EraError::Plugin(e) => e,
+            other => Error::Other(other.to_string()),
+        }
+    }
+}
+
+/// A corrupt entry discovered by [`EraFormat::verify`].
+#[derive(Clone, Debug)]
+pub struct CorruptEntry {
+    /// Item index (matches [`ArchiveReader::get_item`]).
+    pub index: usize,
+    /// The `tiger128` digest stored in the archive.
+    pub expected: [u8; 16],
+    /// The digest actually computed over the decompressed bytes.
+    pub actual: [u8; 16],
 }
 
 /// Extended item info that tracks the original ERA entry index.
@@ -30,8 +125,79 @@ struct EraItem {
     info: ArchiveItem,
     /// Original index in the ERA archive (entry 0 is filename table)
     era_index: usize,
+    /// The exact on-disk filename bytes, retained so that a `CopyExisting`
+    /// update re-writes the original name rather than a lossy re-encoding.
+    raw_name: Vec<u8>,
+}
+
+/// Single-byte code page used to decode legacy ERA filenames that are not
+/// valid UTF-8, for display only. Decoding is lossy for round-tripping (the
+/// decoded string is UTF-8 and would not re-encode to the original bytes), so
+/// the exact on-disk name is preserved separately via [`EraItem::raw_name`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CodePage {
+    /// IBM PC code page 437 (the DOS-era default).
+    Cp437,
+    /// Windows-1252 (Western European).
+    Windows1252,
+}
+
+impl Default for CodePage {
+    fn default() -> Self {
+        CodePage::Cp437
+    }
 }
 
+impl CodePage {
+    /// Map a single byte (0x80..=0xFF; bytes below 0x80 are ASCII) to its
+    /// Unicode scalar under this code page.
+    fn decode_byte(self, b: u8) -> char {
+        if b < 0x80 {
+            return b as char;
+        }
+        match self {
+            CodePage::Cp437 => CP437_HIGH[(b - 0x80) as usize],
+            CodePage::Windows1252 => WINDOWS1252_HIGH[(b - 0x80) as usize],
+        }
+    }
+
+    /// Decode raw filename bytes: valid UTF-8 is taken as-is, otherwise each
+    /// byte is mapped through this code page.
+    fn decode(self, raw: &[u8]) -> String {
+        match std::str::from_utf8(raw) {
+            Ok(s) => s.to_string(),
+            Err(_) => raw.iter().map(|&b| self.decode_byte(b)).collect(),
+        }
+    }
+}
+
+/// CP437 mapping for bytes 0x80..=0xFF.
+#[rustfmt::skip]
+const CP437_HIGH: [char; 128] = [
+    'Ç','ü','é','â','ä','à','å','ç','ê','ë','è','ï','î','ì','Ä','Å',
+    'É','æ','Æ','ô','ö','ò','û','ù','ÿ','Ö','Ü','¢','£','¥','₧','ƒ',
+    'á','í','ó','ú','ñ','Ñ','ª','º','¿','⌐','¬','½','¼','¡','«','»',
+    '░','▒','▓','│','┤','╡','╢','╖','╕','╣','║','╗','╝','╜','╛','┐',
+    '└','┴','┬','├','─','┼','╞','╟','╚','╔','╩','╦','╠','═','╬','╧',
+    '╨','╤','╥','╙','╘','╒','╓','╫','╪','┘','┌','█','▄','▌','▐','▀',
+    'α','ß','Γ','π','Σ','σ','µ','τ','Φ','Θ','Ω','δ','∞','φ','ε','∩',
+    '≡','±','≥','≤','⌠','⌡','÷','≈','°','∙','·','√','ⁿ','²','■','\u{00a0}',
+];
+
+/// Windows-1252 mapping for bytes 0x80..=0xFF. Undefined slots map to the
+/// replacement character.
+#[rustfmt::skip]
+const WINDOWS1252_HIGH: [char; 128] = [
+    '€','\u{fffd}','‚','ƒ','„','…','†','‡','ˆ','‰','Š','‹','Œ','\u{fffd}','Ž','\u{fffd}',
+    '\u{fffd}','‘','’','“','”','•','–','—','˜','™','š','›','œ','\u{fffd}','ž','Ÿ',
+    '\u{00a0}','¡','¢','£','¤','¥','¦','§','¨','©','ª','«','¬','\u{00ad}','®','¯',
+    '°','±','²','³','´','µ','¶','·','¸','¹','º','»','¼','½','¾','¿',
+    'À','Á','Â','Ã','Ä','Å','Æ','Ç','È','É','Ê','Ë','Ì','Í','Î','Ï',
+    'Ð','Ñ','Ò','Ó','Ô','Õ','Ö','×','Ø','Ù','Ú','Û','Ü','Ý','Þ','ß',
+    'à','á','â','ã','ä','å','æ','ç','è','é','ê','ë','ì','í','î','ï',
+    'ð','ñ','ò','ó','ô','õ','ö','÷','ø','ù','ú','û','ü','ý','þ','ÿ',
+];
+
 // =============================================================================
 // ArchiveFormat implementation
 // =============================================================================
@@ -77,49 +243,252 @@ impl ArchiveFormat for EraFormat {
 }
 
 // =============================================================================
-// ArchiveReader implementation
+// Inherent API
 // =============================================================================
 
-impl ArchiveReader for EraFormat {
-    fn open(&mut self, reader: &mut dyn ReadSeek, size: u64) -> Result<()> {
+impl EraFormat {
+    /// Open an archive in random-access mode.
+    ///
+    /// Unlike [`ArchiveReader::open`], this does not eagerly decrypt and
+    /// decompress every entry. Only entry 0 (the filename/index table) is
+    /// parsed up front; each item's byte offset and chunk size are recorded so
+    /// that [`ArchiveReader::extract`] can seek straight to the requested chunk
+    /// and decrypt only that entry. This is the path to prefer when pulling a
+    /// handful of files out of a multi-gigabyte archive, where paying for the
+    /// whole archive up front is wasteful.
+    ///
+    /// TEA encrypts 64-bit blocks, so the underlying [`DecryptReader`] snaps
+    /// seeks to 8-byte boundaries and re-primes the decrypt state at the block
+    /// containing the target offset.
+    /// Auto-detection would need to re-read entry 0 once per candidate keyset,
+    /// but the live reader is consumed when wrapped, so a random-access open
+    /// cannot rewind-and-retry without buffering. It therefore uses a single
+    /// keyset: the one detected by a prior [`ArchiveReader::open`] if present,
+    /// otherwise the first [`Self::candidate_keysets`] entry. Archives with a
+    /// non-default schedule should be probed once with `open` (or have their
+    /// keyset registered and placed first) before opening for random access.
+    pub fn open_random_access(&mut self, reader: Box<dyn ReadSeek>, size: u64) -> Result<()> {
         self.archive_size = size;
 
-        // Read all data into memory (ERA needs full access for decryption)
-        let mut data = Vec::with_capacity(size as usize);
-        reader
-            .read_to_end(&mut data)
-            .map_err(|e| Error::Io(format!("Failed to read archive: {}", e)))?;
+        let keys = self
+            .detected_keys
+            .clone()
+            .or_else(|| self.candidate_keysets().into_iter().next())
+            .ok_or_else(|| Error::InvalidFormat("No candidate keysets available".into()))?;
+
+        let decrypt_reader = DecryptReader::new(reader, keys.clone());
+        // Parse the index table only; entry chunks stay encrypted on the live
+        // stream and are decrypted lazily by `read_entry` on each `extract`
+        // call — the full archive is never materialized in memory.
+        let archive = EraArchive::open_index(decrypt_reader)
+            .map_err(|e| Error::InvalidFormat(format!("Failed to parse ERA: {:?}", e)))?;
 
-        // Store raw data for editing operations
-        self.archive_data = Some(data.clone());
+        self.index_items(&archive);
+        self.archive = Some(archive);
+        self.detected_keys = Some(keys);
+        Ok(())
+    }
 
-        // Decrypt and parse the ERA archive
-        let cursor = Cursor::new(data);
-        let decrypt_reader = DecryptReader::new(cursor, TeaKeys::default_archive_keys());
+    /// Register an additional keyset to try before the built-in table when
+    /// opening an archive. Use this for titles whose schedule is not yet in
+    /// [`builtin_keysets`].
+    pub fn register_keys(&mut self, keys: TeaKeys) {
+        self.extra_keysets.push(keys);
+    }
 
-        let archive = EraArchive::new(decrypt_reader)
-            .map_err(|e| Error::InvalidFormat(format!("Failed to parse ERA: {:?}", e)))?;
+    /// The ordered list of keysets to attempt: caller-registered ones first,
+    /// then the built-in per-title table.
+    fn candidate_keysets(&self) -> Vec<TeaKeys> {
+        let mut keysets = self.extra_keysets.clone();
+        keysets.extend(builtin_keysets());
+        keysets
+    }
+
+    /// Auto-detect the archive's keyset by decrypting entry 0 with each
+    /// candidate and keeping the first whose filename table parses.
+    fn detect_keyset(&self, data: &[u8]) -> Result<(OpenArchive, TeaKeys)> {
+        let mut last_err = None;
+        for keys in self.candidate_keysets() {
+            let reader: Box<dyn ReadSeek> = Box::new(Cursor::new(data.to_vec()));
+            let decrypt_reader = DecryptReader::new(reader, keys.clone());
+            match EraArchive::new(decrypt_reader) {
+                Ok(archive) => return Ok((archive, keys)),
+                Err(e) => last_err = Some(e),
+            }
+        }
+        Err(Error::InvalidFormat(format!(
+            "Failed to parse ERA with any known keyset: {:?}",
+            last_err
+        )))
+    }
+
+    /// Enable or disable Tiger-128 integrity checking on extraction.
+    ///
+    /// Checking is on by default. With it enabled, [`ArchiveReader::extract`]
+    /// computes the Tiger hash (the first 128 bits of the 192-bit digest) over
+    /// the decompressed bytes and compares it against the value stored in the
+    /// archive, failing on mismatch. Disable it to accept entries unconditionally,
+    /// equivalent to calling [`EraFormat::extract_unchecked`] for every item.
+    pub fn set_verify(&mut self, verify: bool) {
+        self.skip_verify = !verify;
+    }
+
+    /// Extract an item, returning a typed [`EraError`] so a checksum failure
+    /// can be matched on directly.
+    ///
+    /// Honours the strict/unchecked mode set by [`EraFormat::set_verify`]; in
+    /// strict mode (the default) a digest mismatch yields
+    /// [`EraError::ChecksumMismatch`].
+    pub fn try_extract(&mut self, index: usize) -> std::result::Result<Vec<u8>, EraError> {
+        let era_index = self.era_index(index)?;
+
+        let archive = self
+            .archive
+            .as_mut()
+            .ok_or_else(|| Error::Other("Archive not open".into()))?;
+
+        let data = archive
+            .read_entry(era_index)
+            .map_err(|e| Error::Other(format!("Failed to read entry {}: {:?}", era_index, e)))?;
+
+        if !self.skip_verify {
+            let (_, _, expected) = archive
+                .read_entry_compressed(era_index)
+                .map_err(|e| Error::Other(format!("Failed to read entry {}: {:?}", era_index, e)))?;
+            let actual = era::tiger128(&data);
+            if actual != expected {
+                return Err(EraError::ChecksumMismatch {
+                    index,
+                    expected,
+                    actual,
+                });
+            }
+        }
+
+        Ok(data)
+    }
 
-        // Convert entries to items, skipping entry 0 (filename table)
+    /// Extract an item without verifying its Tiger-128 digest.
+    ///
+    /// This is the escape hatch for reading entries whose stored digest is
+    /// known to be wrong (e.g. hand-patched archives); prefer
+    /// [`ArchiveReader::extract`], which verifies by default.
+    pub fn extract_unchecked(&mut self, index: usize) -> Result<Vec<u8>> {
+        let era_index = self.era_index(index)?;
+        let archive = self
+            .archive
+            .as_mut()
+            .ok_or_else(|| Error::Other("Archive not open".into()))?;
+        archive
+            .read_entry(era_index)
+            .map_err(|e| Error::Other(format!("Failed to read entry {}: {:?}", era_index, e)))
+    }
+
+    /// Walk every item, verifying its Tiger-128 digest, and report all corrupt
+    /// entries in a single pass.
+    ///
+    /// Unlike extraction, this does not stop at the first digest mismatch: the
+    /// returned vector lists every item whose computed digest does not match
+    /// the stored one, so a corrupt archive can be diagnosed in one go. An
+    /// entry that cannot be read or decompressed at all still ends the walk
+    /// with an error, and no list is returned.
+    pub fn verify(&mut self) -> Result<Vec<CorruptEntry>> {
+        let mut corrupt = Vec::new();
+        for index in 0..self.items.len() {
+            let era_index = self.era_index(index)?;
+            let archive = self
+                .archive
+                .as_mut()
+                .ok_or_else(|| Error::Other("Archive not open".into()))?;
+            let data = archive
+                .read_entry(era_index)
+                .map_err(|e| Error::Other(format!("Failed to read entry {}: {:?}", era_index, e)))?;
+            let (_, _, expected) = archive
+                .read_entry_compressed(era_index)
+                .map_err(|e| Error::Other(format!("Failed to read entry {}: {:?}", era_index, e)))?;
+            let actual = era::tiger128(&data);
+            if actual != expected {
+                corrupt.push(CorruptEntry {
+                    index,
+                    expected,
+                    actual,
+                });
+            }
+        }
+        Ok(corrupt)
+    }
+
+    /// Resolve an item index to its underlying ERA entry index.
+    fn era_index(&self, index: usize) -> Result<usize> {
+        Ok(self
+            .items
+            .get(index)
+            .ok_or(Error::IndexOutOfBounds {
+                index,
+                count: self.items.len(),
+            })?
+            .era_index)
+    }
+
+    /// Set the code page used to decode filenames that are not valid UTF-8.
+    ///
+    /// Call this before [`ArchiveReader::open`]. Clean UTF-8 names are always
+    /// decoded as UTF-8 regardless of this setting; the code page only applies
+    /// to the legacy single-byte names shipped by older Ensemble titles.
+    pub fn set_fallback_encoding(&mut self, encoding: CodePage) {
+        self.fallback_encoding = encoding;
+    }
+
+    /// Populate `items` from a parsed archive, skipping entry 0 (filename table).
+    fn index_items(&mut self, archive: &OpenArchive) {
         self.items.clear();
         for (i, entry) in archive.iter().enumerate() {
             if i == 0 {
                 continue; // Skip filename table
             }
 
-            let name = entry
-                .filename
-                .clone()
-                .unwrap_or_else(|| format!("entry_{}", i));
+            // Keep the exact on-disk bytes for round-tripping, and decode a
+            // display name: UTF-8 when valid, otherwise via the fallback code
+            // page. Backslash separators from the original game-asset tree are
+            // normalized to forward slashes so the hierarchy surfaces properly.
+            let raw_name = entry
+                .filename_bytes()
+                .map(|b| b.to_vec())
+                .unwrap_or_else(|| format!("entry_{}", i).into_bytes());
+            let name = self.fallback_encoding.decode(&raw_name).replace('\\', "/");
 
             self.items.push(EraItem {
                 info: ArchiveItem::file(&name, entry.extra.decomp_size as u64)
                     .with_compressed_size(entry.chunk.size as u64),
                 era_index: i,
+                raw_name,
             });
         }
+    }
+}
+
+// =============================================================================
+// ArchiveReader implementation
+// =============================================================================
 
+impl ArchiveReader for EraFormat {
+    fn open(&mut self, reader: &mut dyn ReadSeek, size: u64) -> Result<()> {
+        self.archive_size = size;
+
+        // Read all data into memory so each candidate keyset can be tried
+        // against it in turn
+        let mut data = Vec::with_capacity(size as usize);
+        reader
+            .read_to_end(&mut data)
+            .map_err(|e| Error::Io(format!("Failed to read archive: {}", e)))?;
+
+        // Try each candidate keyset in turn, selecting the first that decrypts
+        // entry 0 into a valid filename table.
+        let (archive, keys) = self.detect_keyset(&data)?;
+
+        self.index_items(&archive);
         self.archive = Some(archive);
+        self.detected_keys = Some(keys);
         Ok(())
     }
 
@@ -132,30 +501,17 @@ impl ArchiveReader for EraFormat {
     }
 
     fn extract(&mut self, index: usize) -> Result<Vec<u8>> {
-        let era_index = self
-            .items
-            .get(index)
-            .ok_or(Error::IndexOutOfBounds {
-                index,
-                count: self.items.len(),
-            })?
-            .era_index;
-
-        let archive = self
-            .archive
-            .as_mut()
-            .ok_or_else(|| Error::Other("Archive not open".into()))?;
-
-        archive
-            .read_entry(era_index)
-            .map_err(|e| Error::Other(format!("Failed to read entry {}: {:?}", era_index, e)))
+        // The trait boundary can only carry `sevenzip_plugin::Error`; callers
+        // who want to match on a checksum failure should use the typed
+        // [`EraFormat::try_extract`].
+        self.try_extract(index).map_err(Error::from)
     }
 
     fn close(&mut self) {
         self.archive = None;
-        self.archive_data = None;
         self.items.clear();
         self.archive_size = 0;
+        self.detected_keys = None;
     }
 
     fn physical_size(&self) -> Option<u64> {
@@ -204,21 +560,32 @@ impl ArchiveUpdater for EraFormat {
                         .read_entry_compressed(era_index)
                         .map_err(|e| Error::Other(format!("Failed to read entry: {:?}", e)))?;
 
-                    // Get the filename (use new_name if provided, otherwise original)
-                    let filename = new_name.unwrap_or_else(|| {
-                        self.items
-                            .get(index)
-                            .map(|item| item.info.name.clone())
-                            .unwrap_or_else(|| format!("entry_{}", era_index))
-                    });
-
-                    // Add pre-compressed file to skip recompression
-                    era_writer.add_compressed_file(
-                        &filename,
-                        compressed_data,
-                        decomp_size,
-                        tiger128,
-                    );
+                    // Add pre-compressed file to skip recompression. When the
+                    // caller renames, write the new (UTF-8) name; otherwise
+                    // write the retained raw bytes verbatim, so a legacy
+                    // non-UTF-8 name round-trips byte-for-byte rather than
+                    // being lossily re-encoded as UTF-8.
+                    match new_name {
+                        Some(name) => era_writer.add_compressed_file(
+                            &name,
+                            compressed_data,
+                            decomp_size,
+                            tiger128,
+                        ),
+                        None => {
+                            let raw_name = self
+                                .items
+                                .get(index)
+                                .map(|item| item.raw_name.clone())
+                                .unwrap_or_else(|| format!("entry_{}", era_index).into_bytes());
+                            era_writer.add_compressed_file_raw(
+                                &raw_name,
+                                compressed_data,
+                                decomp_size,
+                                tiger128,
+                            );
+                        }
+                    }
                 }
                 UpdateItem::AddNew { name, data } => {
                     // New files need to be compressed (uses parallel compression via rayon)
@@ -227,24 +594,52 @@ impl ArchiveUpdater for EraFormat {
             }
         }
 
-        // Write the new ERA archive to a buffer first (EncryptWriter needs owned writer)
-        let mut buffer = Cursor::new(Vec::new());
-        let keys = TeaKeys::default_archive_keys();
-        let encrypt_writer = EncryptWriter::new(&mut buffer, keys);
+        // Encrypt straight into the caller's sink. `EncryptWriter` keeps an
+        // internal 8-byte TEA residual buffer, flushing completed blocks and
+        // padding the final partial block on `finish`, so the encrypted output
+        // is never buffered as a whole. The entries queued on `era_writer`
+        // above (copied compressed data and new files) are still held in
+        // memory until `write` runs.
+        let mut counting = CountingWriter::new(writer);
+
+        // Re-encrypt with the keyset detected when the archive was opened, so a
+        // non-default title's archive round-trips with its own schedule.
+        let keys = self
+            .detected_keys
+            .clone()
+            .unwrap_or_else(TeaKeys::default_archive_keys);
+        let encrypt_writer = EncryptWriter::new(&mut counting, keys);
 
         era_writer
             .write(encrypt_writer)
             .map_err(|e| Error::Other(format!("Failed to write ERA: {}", e)))?;
 
-        // Write the buffer to the output
-        let output_data = buffer.into_inner();
-        let len = output_data.len() as u64;
+        Ok(counting.written)
+    }
+}
+
+/// A `Write` adapter that tallies the number of bytes forwarded to the inner
+/// sink, so `update_streaming` can report the archive size without buffering it.
+struct CountingWriter<'a> {
+    inner: &'a mut dyn Write,
+    written: u64,
+}
 
-        writer
-            .write_all(&output_data)
-            .map_err(|e| Error::Io(format!("Failed to write output: {}", e)))?;
+impl<'a> CountingWriter<'a> {
+    fn new(inner: &'a mut dyn Write) -> Self {
+        Self { inner, written: 0 }
+    }
+}
+
+impl Write for CountingWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.written += n as u64;
+        Ok(n)
+    }
 
-        Ok(len)
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
     }
 }
 